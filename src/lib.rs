@@ -1,8 +1,12 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-/// Represents a rectangle within the arena. Can be passed to `free` on the arena to deallocate the
-/// rectangle.
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+/// Represents a rectangle within the arena. The [`AllocId`] returned alongside it from
+/// `Arena::alloc` is what should be passed to `free` to deallocate it.
 pub struct Rectangle {
     pub x: u32,
     pub y: u32,
@@ -82,51 +86,179 @@ impl Rectangle {
     }
 }
 
-/// Returns the number of rectangles coalesced
-fn coalesce_all(rects: &mut RectMap) -> usize {
-    let mut remove = vec![];
-    let mut new_rects = vec![];
-
-    for (i, (id1, rect1)) in rects.iter().enumerate() {
-        for (id2, rect2) in rects.iter().skip(i + 1) {
-            if let Some(rect) = rect1.coalesce(rect2) {
-                remove.push(*id1);
-                remove.push(*id2);
-                new_rects.push(rect);
-            }
+/// A guillotine free set: free rectangles are bucketed by a quantized height class (their bit
+/// length) so that a fit query only has to inspect candidates of a compatible height instead of
+/// scanning every free rectangle. Since bit length is monotonic in height, every rectangle tall
+/// enough to satisfy a request lives in a bucket at or above the request's own height class.
+///
+/// Free rectangles are also indexed by each of their four edges, keyed on the coordinates an
+/// abutting neighbour on that side would have to match exactly (same x/width for a vertical
+/// neighbour, same y/height for a horizontal one). Because free rectangles never overlap, each
+/// edge key maps to at most one rectangle, so `insert_merged` can look up a rect's neighbours in
+/// O(1) instead of scanning the whole free set.
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+struct FreeSet {
+    rects: RectMap,
+    buckets: BTreeMap<u32, Vec<RectId>>,
+    by_top: HashMap<(u32, u32, u32), RectId>,
+    by_bottom: HashMap<(u32, u32, u32), RectId>,
+    by_left: HashMap<(u32, u32, u32), RectId>,
+    by_right: HashMap<(u32, u32, u32), RectId>,
+}
+
+impl FreeSet {
+    fn new() -> Self {
+        Self {
+            rects: HashMap::new(),
+            buckets: BTreeMap::new(),
+            by_top: HashMap::new(),
+            by_bottom: HashMap::new(),
+            by_left: HashMap::new(),
+            by_right: HashMap::new(),
         }
     }
 
-    let num_coalesced = new_rects.len();
+    fn height_class(height: u32) -> u32 {
+        32 - height.leading_zeros()
+    }
+
+    fn insert(&mut self, rect: Rectangle) {
+        let id = rect.id();
+        self.buckets
+            .entry(Self::height_class(rect.height))
+            .or_default()
+            .push(id);
+        self.by_top.insert((rect.x, rect.width, rect.y), id);
+        self.by_bottom.insert((rect.x, rect.width, rect.end_y()), id);
+        self.by_left.insert((rect.y, rect.height, rect.x), id);
+        self.by_right.insert((rect.y, rect.height, rect.end_x()), id);
+        self.rects.insert(id, rect);
+    }
+
+    fn remove(&mut self, id: &RectId) -> Option<Rectangle> {
+        let rect = self.rects.remove(id)?;
+        if let Some(bucket) = self.buckets.get_mut(&Self::height_class(rect.height)) {
+            if let Some(pos) = bucket.iter().position(|bucketed_id| bucketed_id == id) {
+                bucket.swap_remove(pos);
+            }
+        }
+        self.by_top.remove(&(rect.x, rect.width, rect.y));
+        self.by_bottom.remove(&(rect.x, rect.width, rect.end_y()));
+        self.by_left.remove(&(rect.y, rect.height, rect.x));
+        self.by_right.remove(&(rect.y, rect.height, rect.end_x()));
+        Some(rect)
+    }
 
-    for id in remove {
-        rects.remove(&id);
+    /// Finds the best free rectangle for the given size under `heuristic`, scanning only the
+    /// height-class buckets compatible with `height`.
+    fn find(&self, width: u32, height: u32, heuristic: Heuristic) -> Option<Rectangle> {
+        let mut candidates = self
+            .buckets
+            .range(Self::height_class(height)..)
+            .flat_map(|(_, ids)| ids.iter())
+            .filter_map(|id| self.rects.get(id))
+            .filter(|rect| rect.width >= width && rect.height >= height);
+
+        match heuristic {
+            Heuristic::FirstFit => candidates.next().cloned(),
+            Heuristic::BestShortSideFit => candidates
+                .min_by_key(|rect| {
+                    let x_leftover = rect.width - width;
+                    let y_leftover = rect.height - height;
+                    (x_leftover.min(y_leftover), x_leftover.max(y_leftover))
+                })
+                .cloned(),
+        }
     }
 
-    for rect in new_rects {
-        append_rect(rects, rect);
+    /// Finds a free rectangle directly touching one of `rect`'s four edges, i.e. one that
+    /// `rect.coalesce` would actually merge with.
+    fn find_neighbour(&self, rect: &Rectangle) -> Option<RectId> {
+        self.by_top
+            .get(&(rect.x, rect.width, rect.end_y()))
+            .or_else(|| self.by_bottom.get(&(rect.x, rect.width, rect.y)))
+            .or_else(|| self.by_left.get(&(rect.y, rect.height, rect.end_x())))
+            .or_else(|| self.by_right.get(&(rect.y, rect.height, rect.x)))
+            .copied()
     }
 
-    if num_coalesced > 0 {
-        coalesce_all(rects);
+    /// Inserts `rect`, first merging it with any free rectangles it directly touches. Unlike a
+    /// full pairwise coalescing sweep, each merge step finds its neighbour with an O(1) edge
+    /// lookup via [`Self::find_neighbour`] rather than a scan of the whole free set; removing
+    /// the merged neighbour then scans only that neighbour's height-class bucket.
+    fn insert_merged(&mut self, mut rect: Rectangle) {
+        while let Some(neighbour_id) = self.find_neighbour(&rect) {
+            let other = self.rects.get(&neighbour_id).expect("indexed rect must exist");
+            let merged = rect
+                .coalesce(other)
+                .expect("edge-indexed neighbour must coalesce");
+            self.remove(&neighbour_id);
+            rect = merged;
+        }
+
+        self.insert(rect);
     }
+}
 
-    num_coalesced
+/// Placement strategy used by [`Arena::alloc`] to choose which free rectangle to carve an
+/// allocation out of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub enum Heuristic {
+    /// Use the first free rectangle that is large enough, in whatever order the free set
+    /// happens to yield them. Cheap, but prone to fragmentation under mixed sizes.
+    FirstFit,
+    /// MaxRects "Best Short Side Fit": among free rectangles that fit, choose the one
+    /// minimizing `(short_side_leftover, long_side_leftover)`, where `short_side_leftover` is
+    /// the smaller of the leftover width and leftover height after placing the allocation.
+    BestShortSideFit,
+}
+
+/// An opaque handle to an allocation made by [`Arena::alloc`], required to `free` it again.
+///
+/// Handles are slab-indexed and generation-counted: freeing a slot bumps its generation, so a
+/// stale or duplicate `AllocId` (even one whose index a later allocation has reused) is
+/// rejected with [`Error::RectangleNotFound`] instead of silently freeing the wrong rectangle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub struct AllocId {
+    index: u32,
+    generation: u16,
+}
+
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+struct Slot {
+    rect: Option<Rectangle>,
+    generation: u16,
 }
 
 /// A 2D arena for allocating rectangles.
+///
+/// With the `serialization` feature enabled, an `Arena` can be round-tripped through serde and
+/// will reproduce identical subsequent `alloc` behaviour. Note that [`ShelfArena`] does not
+/// derive `Serialize`/`Deserialize`, even with the feature enabled.
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct Arena {
     width: u32,
     height: u32,
-    allocated: RectMap,
-    free: RectMap,
+    heuristic: Heuristic,
+    used_area: u64,
+    allocated: Vec<Slot>,
+    free_slots: Vec<u32>,
+    free: FreeSet,
 }
 
 impl Arena {
-    /// Create a new arena with the given width and height.
+    /// Create a new arena with the given width and height, using the
+    /// [`Heuristic::BestShortSideFit`] placement strategy.
     pub fn new(width: u32, height: u32) -> Self {
-        let mut free = HashMap::new();
-        append_rect(&mut free, Rectangle {
+        Self::with_heuristic(width, height, Heuristic::BestShortSideFit)
+    }
+
+    /// Create a new arena with the given width, height and placement [`Heuristic`].
+    pub fn with_heuristic(width: u32, height: u32, heuristic: Heuristic) -> Self {
+        let mut free = FreeSet::new();
+        free.insert(Rectangle {
             x: 0,
             y: 0,
             width,
@@ -136,107 +268,430 @@ impl Arena {
         Self {
             width,
             height,
-            allocated: HashMap::new(),
+            heuristic,
+            used_area: 0,
+            allocated: Vec::new(),
+            free_slots: Vec::new(),
             free,
         }
     }
 
     /// Allocate a rectangle of the given width and height.
-    /// Returns an error if the size is invalid or there is not enough space remaining.
-    pub fn alloc(&mut self, width: u32, height: u32) -> Result<Rectangle, Error> {
+    /// Returns the rectangle along with the [`AllocId`] needed to `free` it again, or an error
+    /// if the size is invalid or there is not enough space remaining.
+    pub fn alloc(&mut self, width: u32, height: u32) -> Result<(Rectangle, AllocId), Error> {
         if width == 0 || height == 0 || width > self.width || height > self.height {
             return Err(Error::InvalidSize);
         }
 
-        coalesce_all(&mut self.free);
-
-        // Find rect of same width and height
-        if let Some(rect) = self
+        let rect = self
             .free
-            .values()
-            .find(|rect| rect.width == width && rect.height == height)
-            .cloned()
-        {
-            self.free.remove(&rect.id());
-            append_rect(&mut self.allocated, rect.clone());
-            return Ok(rect);
-        }
+            .find(width, height, self.heuristic)
+            .ok_or(Error::OutOfSpace)?;
 
-        // Find rect of same width
-        if let Some(rect) = self
-            .free
-            .values()
-            .find(|rect| rect.width == width && rect.height >= height)
-            .cloned()
-        {
-            self.free.remove(&rect.id());
+        self.free.remove(&rect.id());
+
+        let alloced = if rect.width == width && rect.height == height {
+            rect
+        } else {
             let (alloced, remaining) = rect.split_h(width);
-            append_rect(&mut self.free, remaining);
-            append_rect(&mut self.allocated, alloced.clone());
-            return Ok(alloced);
+            if remaining.width > 0 {
+                self.free.insert(remaining);
+            }
+
+            if alloced.height == height {
+                alloced
+            } else {
+                let (alloced, remaining) = alloced.split_v(height);
+                self.free.insert(remaining);
+                alloced
+            }
+        };
+
+        self.used_area += alloced.width as u64 * alloced.height as u64;
+
+        let index = if let Some(index) = self.free_slots.pop() {
+            self.allocated[index as usize].rect = Some(alloced.clone());
+            index
+        } else {
+            let index = self.allocated.len() as u32;
+            self.allocated.push(Slot {
+                rect: Some(alloced.clone()),
+                generation: 0,
+            });
+            index
+        };
+        let generation = self.allocated[index as usize].generation;
+
+        Ok((alloced, AllocId { index, generation }))
+    }
+
+    /// Deallocate the allocation behind the given id and free its area to be allocated again.
+    /// Returns an error if the id is stale, already freed, or out of range.
+    pub fn free(&mut self, id: AllocId) -> Result<(), Error> {
+        let slot = self
+            .allocated
+            .get_mut(id.index as usize)
+            .ok_or(Error::RectangleNotFound)?;
+
+        if slot.generation != id.generation {
+            return Err(Error::RectangleNotFound);
         }
 
-        // Find rect of same height
-        if let Some(rect) = self
-            .free
-            .values()
-            .find(|rect| rect.height == height && rect.width >= width)
-            .cloned()
-        {
-            self.free.remove(&rect.id());
-            let (alloced, remaining) = rect.split_h(height);
-            append_rect(&mut self.free, remaining);
-            append_rect(&mut self.allocated, alloced.clone());
-            return Ok(alloced);
+        let rect = slot.rect.take().ok_or(Error::RectangleNotFound)?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_slots.push(id.index);
+
+        self.used_area -= rect.width as u64 * rect.height as u64;
+        self.free.insert_merged(rect);
+
+        Ok(())
+    }
+
+    /// Looks up the rectangle behind the given id, if it is still allocated.
+    pub fn get(&self, id: AllocId) -> Option<&Rectangle> {
+        let slot = self.allocated.get(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        slot.rect.as_ref()
+    }
+
+    /// Returns an iterator over all allocated rectangles.
+    pub fn allocated(&self) -> impl Iterator<Item = &Rectangle> {
+        self.allocated.iter().filter_map(|slot| slot.rect.as_ref())
+    }
+
+    /// Returns how much of the arena's area is currently allocated.
+    pub fn used_space(&self) -> UsedSpace {
+        UsedSpace {
+            used_area: self.used_area,
+            total_area: self.width as u64 * self.height as u64,
         }
+    }
 
-        // Find any rect that fits
+    /// Enlarges the arena to `new_width`x`new_height`, keeping all existing allocations at
+    /// their current coordinates. The newly exposed area is added to the free set as the strip
+    /// to the right of the old width (spanning the full new height) and the strip below the old
+    /// height (spanning the old width).
+    /// Returns an error if either new dimension is smaller than the current one.
+    pub fn grow(&mut self, new_width: u32, new_height: u32) -> Result<(), Error> {
+        if new_width < self.width || new_height < self.height {
+            return Err(Error::InvalidSize);
+        }
 
-        if let Some(rect) = self
-            .free
-            .values()
-            .find(|rect| rect.width >= width && rect.height >= height)
-            .cloned()
-        {
-            self.free.remove(&rect.id());
-            let (alloced, remaining) = rect.split_h(width);
-            append_rect(&mut self.free, remaining);
-            let (alloced, remaining) = alloced.split_v(height);
-            append_rect(&mut self.free, remaining);
-            append_rect(&mut self.allocated, alloced.clone());
-            return Ok(alloced);
+        if new_width > self.width {
+            self.free.insert_merged(Rectangle {
+                x: self.width,
+                y: 0,
+                width: new_width - self.width,
+                height: new_height,
+            });
+        }
+
+        if new_height > self.height {
+            self.free.insert_merged(Rectangle {
+                x: 0,
+                y: self.height,
+                width: self.width,
+                height: new_height - self.height,
+            });
         }
 
-        Err(Error::OutOfSpace)
+        self.width = new_width;
+        self.height = new_height;
+
+        Ok(())
     }
+}
 
-    /// Deallocate the given rectangle and free the area to be allocated again.
-    /// Returns an error if the rectangle was not found.
-    pub fn free(&mut self, rect: Rectangle) -> Result<(), Error> {
-        let rect = self
+/// A snapshot of how much of an arena's area is currently allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsedSpace {
+    used_area: u64,
+    total_area: u64,
+}
+
+impl UsedSpace {
+    /// The allocated area.
+    pub fn used(&self) -> u64 {
+        self.used_area
+    }
+
+    /// The total area of the arena.
+    pub fn total(&self) -> u64 {
+        self.total_area
+    }
+
+    /// The remaining, unallocated area.
+    pub fn free(&self) -> u64 {
+        self.total_area - self.used_area
+    }
+}
+
+impl Display for UsedSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = if self.total_area == 0 {
+            0.0
+        } else {
+            self.used_area as f64 / self.total_area as f64 * 100.0
+        };
+
+        write!(
+            f,
+            "{:.1}% used ({}/{})",
+            percent, self.used_area, self.total_area
+        )
+    }
+}
+
+struct Shelf {
+    y: u32,
+    height: u32,
+    cursor: u32,
+    /// Spans within `[0, cursor)` that have been freed and can be handed back out.
+    free_spans: Vec<(u32, u32)>,
+}
+
+impl Shelf {
+    /// Returns `(x, width)` to the shelf's free spans, merging it with any free spans it is
+    /// now adjacent to, and retracting the cursor if the merged span runs right up to it.
+    fn free_span(&mut self, mut x: u32, mut width: u32) {
+        loop {
+            if let Some(pos) = self
+                .free_spans
+                .iter()
+                .position(|&(span_x, span_width)| span_x + span_width == x)
+            {
+                let (span_x, span_width) = self.free_spans.swap_remove(pos);
+                x = span_x;
+                width += span_width;
+                continue;
+            }
+
+            if let Some(pos) = self
+                .free_spans
+                .iter()
+                .position(|&(span_x, _)| x + width == span_x)
+            {
+                let (_, span_width) = self.free_spans.swap_remove(pos);
+                width += span_width;
+                continue;
+            }
+
+            break;
+        }
+
+        if x + width == self.cursor {
+            self.cursor = x;
+        } else {
+            self.free_spans.push((x, width));
+        }
+    }
+}
+
+/// How much taller than the requested height a shelf may be before it is skipped in favour of
+/// opening a new, tightly-sized shelf. Without this, a single tall item early on would strand
+/// every later, much shorter item on its oversized shelf forever.
+const SHELF_HEIGHT_SLACK: u32 = 4;
+
+struct ShelfSlot {
+    entry: Option<(usize, Rectangle)>,
+    generation: u16,
+}
+
+/// A 2D arena that packs rectangles into horizontal shelves rather than guillotine-splitting
+/// free space. Well suited to glyph/sprite atlases: thousands of small rectangles of similar
+/// height pack in near-O(1) amortized time, at the cost of wasting the gap between an item's
+/// height and its shelf's height.
+///
+/// Unlike [`Arena`], `ShelfArena` does not derive `Serialize`/`Deserialize` under the
+/// `serialization` feature.
+pub struct ShelfArena {
+    width: u32,
+    height: u32,
+    used_area: u64,
+    shelves: Vec<Shelf>,
+    allocated: Vec<ShelfSlot>,
+    free_slots: Vec<u32>,
+}
+
+impl ShelfArena {
+    /// Create a new shelf arena with the given width and height.
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            used_area: 0,
+            shelves: Vec::new(),
+            allocated: Vec::new(),
+            free_slots: Vec::new(),
+        }
+    }
+
+    /// Allocate a rectangle of the given width and height onto the best-fitting shelf, opening
+    /// a new shelf below the last one if none of the existing ones fit.
+    /// Returns the rectangle along with the [`AllocId`] needed to `free` it again, or an error
+    /// if the size is invalid or there is not enough space remaining.
+    pub fn alloc(&mut self, width: u32, height: u32) -> Result<(Rectangle, AllocId), Error> {
+        if width == 0 || height == 0 || width > self.width || height > self.height {
+            return Err(Error::InvalidSize);
+        }
+
+        if let Some((shelf_index, span_index, x)) = self.find_free_span(width, height) {
+            let shelf = &mut self.shelves[shelf_index];
+            let (span_x, span_width) = shelf.free_spans.remove(span_index);
+            if span_width > width {
+                shelf.free_spans.push((span_x + width, span_width - width));
+            }
+
+            let rect = Rectangle { x, y: shelf.y, width, height };
+            return Ok(self.commit(shelf_index, rect));
+        }
+
+        if let Some(shelf_index) = self.find_cursor_shelf(width, height) {
+            let shelf = &mut self.shelves[shelf_index];
+            let rect = Rectangle { x: shelf.cursor, y: shelf.y, width, height };
+            shelf.cursor += width;
+            return Ok(self.commit(shelf_index, rect));
+        }
+
+        let y = self.shelves.last().map_or(0, |shelf| shelf.y + shelf.height);
+        if y + height > self.height {
+            return Err(Error::OutOfSpace);
+        }
+
+        let shelf_index = self.shelves.len();
+        self.shelves.push(Shelf {
+            y,
+            height,
+            cursor: width,
+            free_spans: Vec::new(),
+        });
+
+        let rect = Rectangle { x: 0, y, width, height };
+        Ok(self.commit(shelf_index, rect))
+    }
+
+    /// Deallocate the allocation behind the given id, returning its span to its shelf for
+    /// reuse. Fully-empty trailing shelves are collapsed back into free vertical space.
+    /// Returns an error if the id is stale, already freed, or out of range.
+    pub fn free(&mut self, id: AllocId) -> Result<(), Error> {
+        let slot = self
             .allocated
-            .remove(&rect.id())
+            .get_mut(id.index as usize)
             .ok_or(Error::RectangleNotFound)?;
 
-        append_rect(&mut self.free, rect);
+        if slot.generation != id.generation {
+            return Err(Error::RectangleNotFound);
+        }
+
+        let (shelf_index, rect) = slot.entry.take().ok_or(Error::RectangleNotFound)?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_slots.push(id.index);
+        self.used_area -= rect.width as u64 * rect.height as u64;
+
+        self.shelves[shelf_index].free_span(rect.x, rect.width);
+        self.collapse_empty_shelves();
 
         Ok(())
     }
 
+    /// Looks up the rectangle behind the given id, if it is still allocated.
+    pub fn get(&self, id: AllocId) -> Option<&Rectangle> {
+        let slot = self.allocated.get(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        slot.entry.as_ref().map(|(_, rect)| rect)
+    }
+
     /// Returns an iterator over all allocated rectangles.
     pub fn allocated(&self) -> impl Iterator<Item = &Rectangle> {
-        self.allocated.values()
+        self.allocated
+            .iter()
+            .filter_map(|slot| slot.entry.as_ref().map(|(_, rect)| rect))
+    }
+
+    /// Returns how much of the arena's area is currently allocated.
+    pub fn used_space(&self) -> UsedSpace {
+        UsedSpace {
+            used_area: self.used_area,
+            total_area: self.width as u64 * self.height as u64,
+        }
+    }
+
+    /// Finds a freed span, on the shelf whose height is the smallest that still fits `height`
+    /// within [`SHELF_HEIGHT_SLACK`], with enough width left over to fit `width`.
+    fn find_free_span(&self, width: u32, height: u32) -> Option<(usize, usize, u32)> {
+        self.shelves
+            .iter()
+            .enumerate()
+            .filter(|(_, shelf)| {
+                shelf.height >= height && shelf.height - height <= SHELF_HEIGHT_SLACK
+            })
+            .flat_map(|(shelf_index, shelf)| {
+                shelf
+                    .free_spans
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (_, span_width))| *span_width >= width)
+                    .map(move |(span_index, &(x, _))| (shelf_index, span_index, x))
+            })
+            .min_by_key(|(shelf_index, _, _)| self.shelves[*shelf_index].height)
+    }
+
+    /// Finds the shelf whose height is the smallest that still fits `height` within
+    /// [`SHELF_HEIGHT_SLACK`], with enough remaining width at its cursor to fit `width`.
+    fn find_cursor_shelf(&self, width: u32, height: u32) -> Option<usize> {
+        self.shelves
+            .iter()
+            .enumerate()
+            .filter(|(_, shelf)| {
+                shelf.height >= height
+                    && shelf.height - height <= SHELF_HEIGHT_SLACK
+                    && self.width - shelf.cursor >= width
+            })
+            .min_by_key(|(_, shelf)| shelf.height)
+            .map(|(i, _)| i)
+    }
+
+    /// Pops fully-empty shelves off the end, giving their vertical space back to future shelves.
+    fn collapse_empty_shelves(&mut self) {
+        while let Some(shelf) = self.shelves.last() {
+            if shelf.cursor == 0 && shelf.free_spans.is_empty() {
+                self.shelves.pop();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn commit(&mut self, shelf_index: usize, rect: Rectangle) -> (Rectangle, AllocId) {
+        self.used_area += rect.width as u64 * rect.height as u64;
+
+        let index = if let Some(index) = self.free_slots.pop() {
+            self.allocated[index as usize].entry = Some((shelf_index, rect.clone()));
+            index
+        } else {
+            let index = self.allocated.len() as u32;
+            self.allocated.push(ShelfSlot {
+                entry: Some((shelf_index, rect.clone())),
+                generation: 0,
+            });
+            index
+        };
+        let generation = self.allocated[index as usize].generation;
+
+        (rect, AllocId { index, generation })
     }
 }
 
 type RectId = (u32, u32);
 type RectMap = HashMap<RectId, Rectangle>;
 
-fn append_rect(rects: &mut RectMap, rect: Rectangle) {
-    rects.insert(rect.id(), rect);
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub enum Error {
     InvalidSize,
     OutOfSpace,
@@ -255,3 +710,104 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_rejects_double_free() {
+        let mut arena = Arena::new(10, 10);
+        let (_, id) = arena.alloc(5, 5).unwrap();
+
+        arena.free(id).unwrap();
+
+        assert_eq!(arena.free(id), Err(Error::RectangleNotFound));
+    }
+
+    #[test]
+    fn free_rejects_stale_id_after_slot_reuse() {
+        let mut arena = Arena::new(10, 10);
+        let (_, stale) = arena.alloc(5, 5).unwrap();
+        arena.free(stale).unwrap();
+
+        // Reuses the slot `stale` pointed at, whose generation was bumped when it was freed.
+        arena.alloc(5, 5).unwrap();
+
+        assert_eq!(arena.free(stale), Err(Error::RectangleNotFound));
+    }
+
+    #[test]
+    fn get_returns_none_for_freed_and_stale_ids() {
+        let mut arena = Arena::new(10, 10);
+        let (_, id) = arena.alloc(5, 5).unwrap();
+        arena.free(id).unwrap();
+
+        assert_eq!(arena.get(id), None);
+
+        // Reuses the slot `id` pointed at, whose generation was bumped when it was freed.
+        arena.alloc(5, 5).unwrap();
+
+        assert_eq!(arena.get(id), None);
+    }
+
+    #[test]
+    fn shelf_arena_reuses_freed_middle_span() {
+        let mut arena = ShelfArena::new(30, 10);
+        arena.alloc(10, 10).unwrap();
+        let (_, b) = arena.alloc(10, 10).unwrap();
+        arena.alloc(10, 10).unwrap();
+
+        arena.free(b).unwrap();
+
+        // The shelf's cursor is already at 30, so this can only succeed by reusing b's span.
+        let (rect, _) = arena.alloc(10, 10).unwrap();
+        assert_eq!(rect.x, 10);
+        assert_eq!(rect.y, 0);
+    }
+
+    #[test]
+    fn shelf_arena_merges_freed_spans_and_retracts_cursor() {
+        let mut arena = ShelfArena::new(20, 10);
+        let (_, a) = arena.alloc(10, 10).unwrap();
+        let (_, b) = arena.alloc(10, 10).unwrap();
+
+        arena.free(a).unwrap();
+        arena.free(b).unwrap();
+
+        // Only succeeds if both freed spans merged back into one 20-wide span (and the
+        // cursor retracted to 0) rather than being left as two disjoint 10-wide spans.
+        let (rect, _) = arena.alloc(20, 10).unwrap();
+        assert_eq!(rect.x, 0);
+    }
+
+    #[test]
+    fn shelf_arena_collapses_empty_trailing_shelf_for_taller_alloc() {
+        let mut arena = ShelfArena::new(10, 20);
+        arena.alloc(10, 5).unwrap();
+        let (_, b) = arena.alloc(10, 5).unwrap();
+
+        arena.free(b).unwrap();
+
+        // Without collapsing the now-empty second shelf, a new shelf would have to start
+        // below it (at y = 10) and a 15-tall item would not fit in the remaining 10 rows.
+        let (rect, _) = arena.alloc(10, 15).unwrap();
+        assert_eq!(rect.y, 5);
+    }
+
+    #[test]
+    #[cfg(feature = "serialization")]
+    fn arena_round_trip_reproduces_identical_alloc_behaviour() {
+        let mut arena = Arena::new(100, 100);
+        let (_, a) = arena.alloc(20, 20).unwrap();
+        arena.alloc(20, 20).unwrap();
+        arena.free(a).unwrap();
+
+        let bytes = bincode::serialize(&arena).unwrap();
+        let mut restored: Arena = bincode::deserialize(&bytes).unwrap();
+
+        for _ in 0..5 {
+            assert_eq!(arena.alloc(10, 10), restored.alloc(10, 10));
+        }
+    }
+}
+